@@ -31,8 +31,13 @@
 //! - Low Dropout Regulator [ldo](Pwr#ldo)
 //! - Switch Mode Power Supply [smps](Pwr#smps)
 //! - Bypass [bypass](Pwr#pypass)
-//! - SMPS Output at 1.8V, then LDO [smps_1v8_feeds_ldo](Pwr#smps_1v8_feeds_ldo)
-//! - SMPS Output at 2.5V, then LDO [smps_2v5_feeds_ldo](Pwr#smps_2v5_feeds_ldo)
+//! - SMPS feeds LDO, output voltage selected by
+//!   [SmpsSupplyVoltage](SmpsSupplyVoltage)
+//!   [smps_feeds_ldo](Pwr#smps_feeds_ldo)
+//!
+//! This is available on the dual-core (RM0399), RM0455 (SmartRun) and
+//! RM0468 families, which differ only in register field names; this
+//! is handled internally.
 //!
 //! **Note**: Specifying the wrong mode for your hardware will cause
 //! undefined results.
@@ -72,6 +77,18 @@
 //!
 //! - [Enable VOS0](https://github.com/stm32-rs/stm32h7xx-hal/blob/master/examples/vos0.rs)
 //! - [Enable USB regulator](https://github.com/stm32-rs/stm32h7xx-hal/blob/master/examples/usb_serial.rs)
+//!
+//! # Stop Mode
+//!
+//! The regulator voltage used while the system is in DStop is
+//! selected independently of the Run-mode [VoltageScale](VoltageScale),
+//! using the `PWR_CR1.SVOS` field. Select one of
+//! [svos3](Pwr#svos3), [svos4](Pwr#svos4) or [svos5](Pwr#svos5)
+//! before calling `freeze`, then use
+//! [PowerConfiguration::enter_stop](PowerConfiguration#enter_stop) to
+//! put the system into Stop mode and resume again on wake.
+
+use cortex_m::peripheral::SCB;
 
 use crate::rcc::backup::BackupREC;
 use crate::stm32::PWR;
@@ -101,7 +118,11 @@ impl PwrExt for PWR {
             #[cfg(any(feature = "smps"))]
             supply_configuration: SupplyConfiguration::Default,
             target_vos: VoltageScale::Scale1,
+            target_svos: None,
             backup_regulator: false,
+            usb_regulator: false,
+            usb_voltage_detector: false,
+            battery_charging: None,
         }
     }
 }
@@ -114,7 +135,11 @@ pub struct Pwr {
     #[cfg(any(feature = "smps"))]
     supply_configuration: SupplyConfiguration,
     target_vos: VoltageScale,
+    target_svos: Option<StopModeVoltageScale>,
     backup_regulator: bool,
+    usb_regulator: bool,
+    usb_voltage_detector: bool,
+    battery_charging: Option<BatteryChargeResistor>,
 }
 
 /// Voltage Scale
@@ -133,13 +158,103 @@ pub enum VoltageScale {
     Scale3,
 }
 
+/// Maximum `(sys_ck, hclk, pclk)` clock frequencies, in Hz, permitted
+/// at a given [VoltageScale]
+///
+/// Returned by [VoltageScale::max_frequencies]. `pclk` applies to all
+/// APB domains, which share a common ceiling at each voltage scale.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ClockCeilings {
+    /// Maximum `sys_ck` / D1 CPU clock frequency, Hz
+    pub sys_ck: u32,
+    /// Maximum AHB (`hclk`) frequency, Hz
+    pub hclk: u32,
+    /// Maximum APB (`pclk`) frequency, Hz
+    pub pclk: u32,
+}
+
+impl VoltageScale {
+    /// Returns the maximum `sys_ck`/`hclk`/`pclk` frequencies permitted
+    /// at this voltage scale, for the reference manual selected by the
+    /// crate's feature flags.
+    ///
+    /// Returns `None` if this [VoltageScale] is not reachable at all
+    /// on the selected part: `Scale0` (VOS0 boost mode) is only
+    /// reachable on revision V and later RM0433/RM0399/RM0468 parts, and is
+    /// not supported at all on RM0455 (SmartRun) parts.
+    ///
+    /// This is intended for `rcc::freeze` to reject a requested PLL
+    /// configuration that would exceed what the selected
+    /// [VoltageScale] supports. That `rcc::freeze` call site is tracked as
+    /// separate follow-up work and is not wired up by this method on
+    /// its own.
+    pub fn max_frequencies(&self) -> Option<ClockCeilings> {
+        #[cfg(any(feature = "rm0433", feature = "rm0399"))]
+        let ceilings = match self {
+            // VOS0 boost mode, revision V and later only. RM0433 Rev 7 Table 15
+            #[cfg(feature = "revision_v")]
+            VoltageScale::Scale0 => Some((480_000_000, 240_000_000, 120_000_000)),
+            #[cfg(not(feature = "revision_v"))]
+            VoltageScale::Scale0 => None,
+            VoltageScale::Scale1 => Some((400_000_000, 200_000_000, 100_000_000)),
+            VoltageScale::Scale2 => Some((300_000_000, 150_000_000, 75_000_000)),
+            VoltageScale::Scale3 => Some((200_000_000, 100_000_000, 50_000_000)),
+        };
+
+        #[cfg(feature = "rm0455")]
+        let ceilings = match self {
+            // VOS0 (boost mode) is not supported on RM0455 parts
+            VoltageScale::Scale0 => None,
+            // RM0455 Rev 3 Table 24
+            VoltageScale::Scale1 => Some((280_000_000, 140_000_000, 70_000_000)),
+            VoltageScale::Scale2 => Some((225_000_000, 112_500_000, 56_250_000)),
+            VoltageScale::Scale3 => Some((140_000_000, 70_000_000, 35_000_000)),
+        };
+
+        #[cfg(feature = "rm0468")]
+        let ceilings = match self {
+            // VOS0 boost mode, revision V and later only. RM0468 Rev 2 Table 14
+            #[cfg(feature = "revision_v")]
+            VoltageScale::Scale0 => Some((520_000_000, 260_000_000, 130_000_000)),
+            #[cfg(not(feature = "revision_v"))]
+            VoltageScale::Scale0 => None,
+            VoltageScale::Scale1 => Some((400_000_000, 200_000_000, 100_000_000)),
+            VoltageScale::Scale2 => Some((300_000_000, 150_000_000, 75_000_000)),
+            VoltageScale::Scale3 => Some((200_000_000, 100_000_000, 50_000_000)),
+        };
+
+        ceilings.map(|(sys_ck, hclk, pclk)| ClockCeilings {
+            sys_ck,
+            hclk,
+            pclk,
+        })
+    }
+}
+
+/// Stop-mode Voltage Scale
+///
+/// Represents the VCORE regulator output selected by `PWR_CR1.SVOS`
+/// while the system is in DStop. This is independent of the Run-mode
+/// [VoltageScale].
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum StopModeVoltageScale {
+    /// SVOS 3, the highest Stop-mode performance point
+    Scale3 = 0b11,
+    /// SVOS 4
+    Scale4 = 0b10,
+    /// SVOS 5, the lowest power Stop-mode regulator voltage
+    Scale5 = 0b01,
+}
+
 /// Power Configuration
 ///
 /// Generated when the PWR peripheral is frozen. The existence of this
 /// value indicates that the voltage scaling configuration can no
 /// longer be changed.
 pub struct PowerConfiguration {
+    pub(crate) rb: PWR,
     pub(crate) vos: VoltageScale,
+    pub(crate) svos: Option<StopModeVoltageScale>,
     pub(crate) backup: Option<BackupREC>,
 }
 
@@ -152,18 +267,94 @@ impl PowerConfiguration {
     pub fn backup(&mut self) -> Option<BackupREC> {
         self.backup.take()
     }
+
+    /// Enter Stop mode, using the Stop-mode voltage scale selected by
+    /// `Pwr::svos3()`/`svos4()`/`svos5()` prior to `freeze()`.
+    ///
+    /// This sets `SCB.SCR.SLEEPDEEP`, clears `CPUCR.PDDS_Dn` so that
+    /// the D1/D2/D3 domains enter Stop (rather than Standby), and sets
+    /// `CR1.LPDS` as required by the chosen Stop-mode voltage scale,
+    /// then executes `WFI`. Execution resumes here once an interrupt
+    /// wakes the CPU, at which point the Run-mode VOS configured by
+    /// `freeze()` is restored before returning.
+    ///
+    /// Panics if no Stop-mode voltage scale was selected before
+    /// `freeze()`.
+    pub fn enter_stop(&self, scb: &mut SCB) {
+        let svos = self.svos.expect(
+            "No Stop-mode voltage scale selected. Call `svos3()`, \
+             `svos4()` or `svos5()` on `Pwr` before `freeze()`.",
+        );
+
+        // Select the Stop-mode regulator voltage, and set CR1.LPDS
+        // (low-power regulator mode in Stop) for every scale except
+        // SVOS3: per RM0433 Rev 7 6.8.4, SVOS only lowers VCORE in
+        // Stop when LPDS=1 — with LPDS=0 only SVOS3 has any effect,
+        // and SVOS4/SVOS5 would silently be ignored
+        let lpds = !matches!(svos, StopModeVoltageScale::Scale3);
+        self.rb.cr1.modify(|_, w| unsafe {
+            w.svos().bits(svos as u8).lpds().bit(lpds)
+        });
+
+        // Clear the PDDS_Dn bits so that DStop (rather than DStandby)
+        // is entered for each domain. This is unrelated to LPDS above,
+        // which selects the regulator mode used while in Stop
+        self.rb.cpucr.modify(|_, w| {
+            w.pdds_d1()
+                .clear_bit()
+                .pdds_d2()
+                .clear_bit()
+                .pdds_d3()
+                .clear_bit()
+        });
+
+        scb.set_sleepdeep();
+        cortex_m::asm::wfi();
+        scb.clear_sleepdeep();
+
+        // We have returned from Stop mode. Restore Run-mode VOS
+        while d3cr!(self.rb).read().vosrdy().bit_is_clear() {}
+    }
+}
+
+/// SMPS output voltage level
+///
+/// Selects the output voltage of the SMPS step-down converter when it
+/// feeds the LDO, on parts where this is configurable. Refer to
+/// RM0399 Rev 3 Table 32, RM0455 Rev 3 Table 46 and RM0468 Rev 2
+/// Table 36.
+#[cfg(any(feature = "smps"))]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum SmpsSupplyVoltage {
+    /// SMPS output set to 1.8V
+    V1V8,
+    /// SMPS output set to 2.5V
+    V2V5,
+}
+
+/// VBAT battery charging resistor
+///
+/// Selects the series resistor used by the backup domain VBAT
+/// charging circuit to trickle-charge a battery or supercapacitor on
+/// VBAT from VDD.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum BatteryChargeResistor {
+    /// 5 kΩ charging resistor
+    R5kOhm,
+    /// 1.5 kΩ charging resistor
+    R1k5Ohm,
 }
 
-/// SMPS Supply Configuration - Dual Core parts
+/// SMPS Supply Configuration
 ///
-/// Refer to RM0399 Rev 3 Table 32.
+/// Refer to RM0399 Rev 3 Table 32, RM0455 Rev 3 Table 46 and RM0468
+/// Rev 2 Table 36.
 #[cfg(any(feature = "smps"))]
 enum SupplyConfiguration {
-    Default = 0,
+    Default,
     LDOSupply,
     DirectSMPS,
-    SMPSFeedsIntoLDO1V8,
-    SMPSFeedsIntoLDO2V5,
+    SMPSFeedsIntoLDO(SmpsSupplyVoltage),
     // External SMPS loads not supported
     Bypass,
 }
@@ -244,20 +435,15 @@ impl Pwr {
                 assert!(smps_en!(self.rb.cr3.read()).bit_is_set(), "{}", error);
                 assert!(self.rb.cr3.read().ldoen().bit_is_clear(), "{}", error);
             }
-            SMPSFeedsIntoLDO1V8 => {
-                assert!(smps_en!(self.rb.cr3.read()).bit_is_set(), "{}", error);
-                assert!(self.rb.cr3.read().ldoen().bit_is_clear(), "{}", error);
-                assert!(
-                    smps_level!(self.rb.cr3.read()).bits() == 1,
-                    "{}",
-                    error
-                );
-            }
-            SMPSFeedsIntoLDO2V5 => {
+            SMPSFeedsIntoLDO(voltage) => {
                 assert!(smps_en!(self.rb.cr3.read()).bit_is_set(), "{}", error);
                 assert!(self.rb.cr3.read().ldoen().bit_is_clear(), "{}", error);
+                let expected = match voltage {
+                    SmpsSupplyVoltage::V1V8 => 1,
+                    SmpsSupplyVoltage::V2V5 => 2,
+                };
                 assert!(
-                    smps_level!(self.rb.cr3.read()).bits() == 2,
+                    smps_level!(self.rb.cr3.read()).bits() == expected,
                     "{}",
                     error
                 );
@@ -336,20 +522,18 @@ impl Pwr {
                            adjusted by VOS. SMPS power mode will follow \
                            the system low-power mode",
         Bypass: bypass, "VCORE is supplied from an external source",
-        SMPSFeedsIntoLDO1V8:
-        smps_1v8_feeds_ldo, "VCORE power domains supplied from the LDO. \
-                         LDO voltage adjusted by VOS. \
-                         LDO power mode will follow the system \
-                         low-power mode. SMPS output voltage set to \
-                         1.8V. SMPS power mode will follow \
-                         the system low-power mode",
-        SMPSFeedsIntoLDO2V5:
-        smps_2v5_feeds_ldo, "VCORE power domains supplied from the LDO. \
-                         LDO voltage adjusted by VOS. \
-                         LDO power mode will follow the system \
-                         low-power mode. SMPS output voltage set to \
-                         2.5V. SMPS power mode will follow \
-                         the system low-power mode",
+    }
+
+    /// VCORE power domains supplied from the LDO. LDO voltage adjusted
+    /// by VOS. LDO power mode will follow the system low-power mode.
+    /// SMPS output voltage set by `voltage`. SMPS power mode will
+    /// follow the system low-power mode
+    #[cfg(any(feature = "smps"))]
+    #[must_use]
+    pub fn smps_feeds_ldo(mut self, voltage: SmpsSupplyVoltage) -> Self {
+        self.supply_configuration =
+            SupplyConfiguration::SMPSFeedsIntoLDO(voltage);
+        self
     }
 
     #[cfg(all(
@@ -380,6 +564,27 @@ impl Pwr {
         self
     }
 
+    /// Configure Stop-mode Voltage Scale 3, the highest Stop-mode
+    /// performance point
+    #[must_use]
+    pub fn svos3(mut self) -> Self {
+        self.target_svos = Some(StopModeVoltageScale::Scale3);
+        self
+    }
+    /// Configure Stop-mode Voltage Scale 4
+    #[must_use]
+    pub fn svos4(mut self) -> Self {
+        self.target_svos = Some(StopModeVoltageScale::Scale4);
+        self
+    }
+    /// Configure Stop-mode Voltage Scale 5, the lowest power Stop-mode
+    /// regulator voltage
+    #[must_use]
+    pub fn svos5(mut self) -> Self {
+        self.target_svos = Some(StopModeVoltageScale::Scale5);
+        self
+    }
+
     /// Enable the backup domain voltage regulator
     ///
     /// The backup domain voltage regulator maintains the contents of backup SRAM
@@ -390,6 +595,43 @@ impl Pwr {
         self
     }
 
+    /// Enable VBAT battery charging through the given series resistor
+    ///
+    /// Trickle-charges a battery or supercapacitor on VBAT from VDD
+    /// through `resistor`. This sets `CR3.VBE`/`CR3.VBRS`, which live
+    /// alongside the supply-configuration bits written once per POR,
+    /// but are not covered by the lower-byte-of-CR3 readback check
+    /// performed for the supply configuration.
+    #[must_use]
+    pub fn battery_charging(mut self, resistor: BatteryChargeResistor) -> Self {
+        self.battery_charging = Some(resistor);
+        self
+    }
+
+    /// Enable the USB voltage regulator
+    ///
+    /// This powers the USB OTG HS PHY from the internal 3.3V USB
+    /// regulator.
+    #[must_use]
+    pub fn usb_regulator(mut self) -> Self {
+        self.usb_regulator = true;
+        self
+    }
+
+    /// Enable the USB voltage detector without enabling the USB
+    /// regulator
+    ///
+    /// Monitors VDD33USB so that the PHY is only enabled once its
+    /// supply is within range. Use this when VDD33USB is supplied
+    /// externally rather than from the internal regulator. Has no
+    /// effect if [usb_regulator](Pwr#usb_regulator) is also selected,
+    /// since that already enables the voltage detector.
+    #[must_use]
+    pub fn usb_voltage_detector(mut self) -> Self {
+        self.usb_voltage_detector = true;
+        self
+    }
+
     pub fn freeze(self) -> PowerConfiguration {
         // NB. The lower bytes of CR3 can only be written once after
         // POR, and must be written with a valid combination. Refer to
@@ -399,23 +641,39 @@ impl Pwr {
 
         #[cfg(not(feature = "smps"))]
         self.rb.cr3.modify(|_, w| {
-            w.scuen().set_bit().ldoen().set_bit().bypass().clear_bit()
+            let w =
+                w.scuen().set_bit().ldoen().set_bit().bypass().clear_bit();
+            match self.battery_charging {
+                Some(BatteryChargeResistor::R5kOhm) => {
+                    w.vbe().set_bit().vbrs().clear_bit()
+                }
+                Some(BatteryChargeResistor::R1k5Ohm) => {
+                    w.vbe().set_bit().vbrs().set_bit()
+                }
+                None => w,
+            }
         });
 
         #[cfg(any(feature = "smps"))]
         self.rb.cr3.modify(|_, w| {
             use SupplyConfiguration::*;
 
-            match self.supply_configuration {
+            // NB. Unlike the non-smps path above, SCUEN does not apply
+            // here: this branch is only compiled for the dual-core
+            // (RM0399), RM0455 and RM0468 families, which latch the
+            // supply configuration directly from SDEN (SMPSEN on
+            // RM0455)/LDOEN/BYPASS
+            // without a separate update-enable bit.
+            let w = match self.supply_configuration {
                 LDOSupply => smps_en!(w).clear_bit().ldoen().set_bit(),
                 DirectSMPS => smps_en!(w).set_bit().ldoen().clear_bit(),
-                SMPSFeedsIntoLDO1V8 => unsafe {
-                    let reg = smps_en!(w).set_bit().ldoen().set_bit();
-                    smps_level!(reg).bits(1)
-                },
-                SMPSFeedsIntoLDO2V5 => unsafe {
+                SMPSFeedsIntoLDO(voltage) => unsafe {
                     let reg = smps_en!(w).set_bit().ldoen().set_bit();
-                    smps_level!(reg).bits(2)
+                    let level = match voltage {
+                        SmpsSupplyVoltage::V1V8 => 1,
+                        SmpsSupplyVoltage::V2V5 => 2,
+                    };
+                    smps_level!(reg).bits(level)
                 },
                 Bypass => smps_en!(w)
                     .clear_bit()
@@ -430,6 +688,16 @@ impl Pwr {
                     // anything here.
                     w
                 }
+            };
+
+            match self.battery_charging {
+                Some(BatteryChargeResistor::R5kOhm) => {
+                    w.vbe().set_bit().vbrs().clear_bit()
+                }
+                Some(BatteryChargeResistor::R1k5Ohm) => {
+                    w.vbe().set_bit().vbrs().set_bit()
+                }
+                None => w,
             }
         });
         // Verify supply configuration, panics if these values read
@@ -499,10 +767,30 @@ impl Pwr {
             while self.rb.cr2.read().brrdy().bit_is_clear() {}
         }
 
+        if self.usb_regulator {
+            self.rb
+                .cr3
+                .modify(|_, w| w.usbregen().set_bit().usb33den().set_bit());
+            while self.rb.cr3.read().usb33rdy().bit_is_clear() {}
+        } else if self.usb_voltage_detector {
+            self.rb.cr3.modify(|_, w| w.usb33den().set_bit());
+            while self.rb.cr3.read().usb33rdy().bit_is_clear() {}
+        }
+
         let backup = unsafe { BackupREC::new_singleton(self.backup_regulator) };
 
+        // Program the selected Stop-mode voltage scale, if any. This is
+        // independent of the Run-mode VOS field above
+        if let Some(svos) = self.target_svos {
+            self.rb
+                .cr1
+                .modify(|_, w| unsafe { w.svos().bits(svos as u8) });
+        }
+
         PowerConfiguration {
+            rb: self.rb,
             vos,
+            svos: self.target_svos,
             backup: Some(backup),
         }
     }